@@ -1,10 +1,14 @@
-use std::{error, fmt};
+use std::{cmp, error, fmt};
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use std::sync::Arc;
 
 use http;
-use futures::{Async, Poll};
+use futures::{Async, Future, Poll};
+use tokio_timer::Delay;
 use tower_service as tower;
 use tower_balance::{choose, load, Balance};
 use tower_buffer::Buffer;
@@ -17,6 +21,7 @@ use conduit_proxy_router::Recognize;
 use bind::{self, Bind, Protocol};
 use control::destination::{self, Bind as BindTrait, Resolution};
 use ctx;
+use dns;
 use telemetry::sensor::http::{ResponseBody as SensorBody};
 use timeout::Timeout;
 use transparency::{h1, HttpBody};
@@ -27,7 +32,53 @@ type BindProtocol<B> = bind::BindProtocol<Arc<ctx::Proxy>, B>;
 pub struct Outbound<B> {
     bind: Bind<Arc<ctx::Proxy>, B>,
     discovery: destination::Resolver,
+    dns: dns::Resolver,
     bind_timeout: Duration,
+    balancer_policy: BalancerPolicy,
+    maxrate: f64,
+    burst: f64,
+    watermark_offset: f64,
+}
+
+/// With no explicit rate configured, the token bucket is sized so large
+/// that it never meaningfully sheds; concurrency is still bounded by
+/// `MAX_IN_FLIGHT`.
+const DEFAULT_MAXRATE: f64 = 1_000_000.0;
+const DEFAULT_BURST: f64 = 1_000_000.0;
+
+/// `high = maxrate`, `low = maxrate - watermark_offset`. These watermarks
+/// apply to the token bucket only; `MAX_IN_FLIGHT` is a plain cap.
+const DEFAULT_WATERMARK_OFFSET: f64 = 10.0;
+
+/// Bounds on how often a DNS fallback resolution may be refreshed, no
+/// matter what TTL the record carries.
+const MIN_DNS_REFRESH: Duration = Duration::from_secs(5);
+const MAX_DNS_REFRESH: Duration = Duration::from_secs(60);
+
+/// The strategy used to select an endpoint from a destination's set of
+/// discovered replicas.
+///
+/// In every case, replicas are still load-tracked via peak-EWMA (this is
+/// what `PendingUntilFirstData` instruments), so the `Response` type
+/// stays uniform; the policies differ only in how (or whether) they read
+/// that load when choosing.
+#[derive(Copy, Clone, Debug)]
+pub enum BalancerPolicy {
+    /// Sample two replicas at random and pick the less-loaded of the
+    /// two. This is Finagle's default and what Conduit has always done.
+    PeakEwmaP2C { decay: Duration },
+    /// Scan every ready replica and pick the least-loaded one outright.
+    /// More predictable than P2C for bursty or long-lived-stream
+    /// workloads, at the cost of scanning all replicas per choice.
+    LeastLoaded,
+    /// Cycle through replicas in order, ignoring load entirely.
+    RoundRobin,
+}
+
+impl Default for BalancerPolicy {
+    fn default() -> Self {
+        BalancerPolicy::PeakEwmaP2C { decay: DEFAULT_DECAY }
+    }
 }
 
 const MAX_IN_FLIGHT: usize = 10_000;
@@ -46,15 +97,41 @@ pub enum Destination {
 impl<B> Outbound<B> {
     pub fn new(bind: Bind<Arc<ctx::Proxy>, B>,
                discovery: destination::Resolver,
+               dns: dns::Resolver,
                bind_timeout: Duration)
                -> Outbound<B> {
         Self {
             bind,
             discovery,
+            dns,
             bind_timeout,
+            balancer_policy: BalancerPolicy::default(),
+            maxrate: DEFAULT_MAXRATE,
+            burst: DEFAULT_BURST,
+            watermark_offset: DEFAULT_WATERMARK_OFFSET,
         }
     }
 
+    /// Configures the load-balancing policy used to select an endpoint
+    /// from a destination's set of discovered replicas.
+    pub fn with_balancer_policy(mut self, policy: BalancerPolicy) -> Self {
+        self.balancer_policy = policy;
+        self
+    }
+
+    /// Configures the per-destination token-bucket request-rate limit:
+    /// `maxrate` tokens refill per second, up to a capacity of `burst`.
+    /// Requests are still admitted once the bucket is empty; shedding
+    /// starts when the token debt exceeds `maxrate` (the high watermark).
+    /// `watermark_offset` sets how far below `maxrate` the low watermark
+    /// sits (see `TokenBucket::new`).
+    pub fn with_rate_limit(mut self, maxrate: f64, burst: f64, watermark_offset: f64) -> Self {
+        self.maxrate = maxrate;
+        self.burst = burst;
+        self.watermark_offset = watermark_offset;
+        self
+    }
+
 
     /// TODO: Return error when `HostAndPort::normalize()` fails.
     /// TODO: Use scheme-appropriate default port.
@@ -121,6 +198,37 @@ impl<B> Outbound<B> {
             }
         }
     }
+
+    /// Detects an HTTP/1.1 request that's asking to be upgraded to h2c
+    /// (HTTP/2 over cleartext), per RFC 7540 section 3.2: `Connection:
+    /// Upgrade`, `Upgrade: h2c`, and an `HTTP2-Settings` header carrying
+    /// the base64url-encoded initial `SETTINGS` frame.
+    ///
+    /// This only covers the upgrade-header form. A client that sends the
+    /// HTTP/2 connection preface directly ("prior knowledge") never looks
+    /// like an `http::Request` at all -- that has to be detected on the
+    /// raw connection bytes, which is out of scope here; see `bind` and
+    /// `transparency::h1` for where that detection and the handshake
+    /// itself belong.
+    fn is_h2c_upgrade(req: &http::Request<B>) -> bool {
+        let headers = req.headers();
+
+        let has_connection_upgrade = headers
+            .get(http::header::CONNECTION)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.split(',').any(|tok| tok.trim().eq_ignore_ascii_case("upgrade")))
+            .unwrap_or(false);
+
+        let has_h2c_upgrade = headers
+            .get(http::header::UPGRADE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.eq_ignore_ascii_case("h2c"))
+            .unwrap_or(false);
+
+        let has_settings = headers.contains_key("http2-settings");
+
+        has_connection_upgrade && has_h2c_upgrade && has_settings
+    }
 }
 
 impl<B> Clone for Outbound<B>
@@ -132,7 +240,12 @@ where
         Self {
             bind: self.bind.clone(),
             discovery: self.discovery.clone(),
+            dns: self.dns.clone(),
             bind_timeout: self.bind_timeout.clone(),
+            balancer_policy: self.balancer_policy,
+            maxrate: self.maxrate,
+            burst: self.burst,
+            watermark_offset: self.watermark_offset,
         }
     }
 }
@@ -150,15 +263,31 @@ where
     type Error = <Self::Service as tower::Service>::Error;
     type Key = (Destination, Protocol);
     type RouteError = bind::BufferSpawnError;
-    type Service = InFlightLimit<Timeout<Buffer<Balance<
-        load::WithPeakEwma<Discovery<B>, PendingUntilFirstData>,
-        choose::PowerOfTwoChoices,
-    >>>>;
+    type Service = InFlightLimit<RateLimit<Timeout<Buffer<Balancer<B>>>>>;
 
     // Route the request by its destination AND PROTOCOL. This prevents HTTP/1
     // requests from being routed to HTTP/2 servers, and vice versa.
+    //
+    // TODO: A request carrying an h2c cleartext upgrade (`Connection:
+    // Upgrade` + `Upgrade: h2c` + `HTTP2-Settings`) is HTTP/2 in disguise,
+    // and should eventually get its own `Protocol` variant and be routed to
+    // the HTTP/2 balancer. That isn't implemented here: doing so without
+    // also performing the 101 switch handshake (which belongs in `bind`/
+    // `transparency::h1`, and doesn't exist in this tree) would dispatch a
+    // plaintext HTTP/1 request over a client connection that never
+    // negotiated h2, breaking it outright. This is deliberately left
+    // unimplemented -- not merely deferred in spirit -- until that
+    // handshake lands; an h2c upgrade request is only logged for now, and
+    // still routed and dispatched like any other HTTP/1 request.
     fn recognize(&self, req: &Self::Request) -> Option<Self::Key> {
         let dest = Self::destination(req)?;
+        if Self::is_h2c_upgrade(req) {
+            debug!(
+                "h2c upgrade requested for {:?}, but the upgrade handshake isn't \
+                 implemented yet; routing as HTTP/1",
+                dest,
+            );
+        }
         let proto = bind::Protocol::detect(req);
         Some((dest, proto))
     }
@@ -176,21 +305,51 @@ where
 
         let resolve = match *dest {
             Destination::Hostname(ref authority) => {
-                Discovery::NamedSvc(self.discovery.resolve(
+                let named = self.discovery.resolve(
                     authority,
                     self.bind.clone().with_protocol(protocol.clone()),
-                ))
+                );
+                let dns = DnsResolve::new(
+                    authority.clone(),
+                    self.bind.clone().with_protocol(protocol.clone()),
+                    self.dns.clone(),
+                );
+                Discovery::Fallback(Fallback {
+                    named,
+                    named_count: 0,
+                    dns,
+                    dns_retractions: Vec::new(),
+                })
             },
             Destination::ImplicitOriginalDst(addr) => {
-                Discovery::ImplicitOriginalDst(Some((addr, self.bind.clone()
-                    .with_protocol(protocol.clone()))))
+                Discovery::ImplicitOriginalDst(ImplicitOriginalDst {
+                    addr,
+                    bind: self.bind.clone().with_protocol(protocol.clone()),
+                    breaker: BreakerCell::closed(),
+                    inserted: false,
+                })
             }
         };
 
         let balance = {
+            let decay = match self.balancer_policy {
+                BalancerPolicy::PeakEwmaP2C { decay } => decay,
+                _ => DEFAULT_DECAY,
+            };
             let instrument = PendingUntilFirstData::default();
-            let loaded = load::WithPeakEwma::new(resolve, DEFAULT_DECAY, instrument);
-            Balance::p2c(loaded)
+            let loaded = load::WithPeakEwma::new(resolve, decay, instrument);
+            match self.balancer_policy {
+                // `choose::PowerOfTwoChoices`/`choose::RoundRobin` need a
+                // seeded RNG to construct, so go through `Balance`'s own
+                // `p2c`/`round_robin` constructors (which seed one)
+                // rather than a `Default` impl they don't have.
+                BalancerPolicy::PeakEwmaP2C { .. } =>
+                    Balancer::PowerOfTwoChoices(Balance::p2c(loaded)),
+                BalancerPolicy::LeastLoaded =>
+                    Balancer::LeastLoaded(Balance::new(loaded, LeastLoaded::default())),
+                BalancerPolicy::RoundRobin =>
+                    Balancer::RoundRobin(Balance::round_robin(loaded)),
+            }
         };
 
         let log = ::logging::proxy().client("out", Dst(dest.clone()))
@@ -200,13 +359,16 @@ where
 
         let timeout = Timeout::new(buffer, self.bind_timeout);
 
-        Ok(InFlightLimit::new(timeout, MAX_IN_FLIGHT))
+        let bucket = TokenBucket::new(self.maxrate, self.burst, self.watermark_offset);
+        let rate_limit = RateLimit::new(timeout, bucket);
+
+        Ok(InFlightLimit::new(rate_limit, MAX_IN_FLIGHT))
     }
 }
 
 pub enum Discovery<B> {
-    NamedSvc(Resolution<BindProtocol<B>>),
-    ImplicitOriginalDst(Option<(SocketAddr, BindProtocol<B>)>),
+    Fallback(Fallback<B>),
+    ImplicitOriginalDst(ImplicitOriginalDst<B>),
 }
 
 impl<B> Discover for Discovery<B>
@@ -218,30 +380,744 @@ where
     type Request = http::Request<B>;
     type Response = bind::HttpResponse;
     type Error = <Self::Service as tower::Service>::Error;
-    type Service = bind::Service<B>;
+    type Service = CircuitBreaker<bind::Service<B>>;
     type DiscoverError = BindError;
 
     fn poll(&mut self) -> Poll<Change<Self::Key, Self::Service>, Self::DiscoverError> {
         match *self {
-            Discovery::NamedSvc(ref mut w) => w.poll()
-                .map_err(|_| BindError::Internal),
-            Discovery::ImplicitOriginalDst(ref mut opt) => {
-                // This "discovers" a single address for an external service
-                // that never has another change. This can mean it floats
-                // in the Balancer forever. However, when we finally add
-                // circuit-breaking, this should be able to take care of itself,
-                // closing down when the connection is no longer usable.
-                if let Some((addr, bind)) = opt.take() {
-                    let svc = bind.bind(&addr.into())
-                        .map_err(|_| BindError::External { addr })?;
-                    Ok(Async::Ready(Change::Insert(addr, svc)))
+            Discovery::Fallback(ref mut f) => f.poll(),
+            Discovery::ImplicitOriginalDst(ref mut ep) => ep.poll(),
+        }
+    }
+}
+
+fn circuit_break<S>(svc: S) -> CircuitBreaker<S> {
+    CircuitBreaker::new(svc, BreakerCell::closed())
+}
+
+/// Resolves a `Destination::Hostname` primarily through the control
+/// plane's `destination::Resolver`. If that `Resolution` hasn't yielded
+/// any endpoint, a plain DNS A-record lookup of the name is consulted as
+/// a fallback, so hostnames the control plane doesn't know about are
+/// still reachable.
+pub struct Fallback<B> {
+    named: Resolution<BindProtocol<B>>,
+    /// The number of endpoints currently known via `named`. Tracked as a
+    /// count (rather than a bool set once and never cleared) because the
+    /// control plane can forget a name it previously knew: each `Remove`
+    /// has to be able to bring this back down to zero and re-enable DNS.
+    named_count: usize,
+    dns: DnsResolve<B>,
+    /// Addresses `dns` previously inserted that must now be retracted,
+    /// because `named` has started reporting endpoints of its own. Drained
+    /// one `Change::Remove` per poll before anything else.
+    dns_retractions: Vec<SocketAddr>,
+}
+
+impl<B> Fallback<B>
+where
+    B: tower_h2::Body + Send + 'static,
+    <B::Data as ::bytes::IntoBuf>::Buf: Send,
+{
+    fn poll(&mut self) -> Poll<Change<SocketAddr, CircuitBreaker<bind::Service<B>>>, BindError> {
+        if let Some(addr) = self.dns_retractions.pop() {
+            return Ok(Async::Ready(Change::Remove(addr)));
+        }
+
+        match self.named.poll().map_err(|_| BindError::Internal)? {
+            Async::Ready(Change::Insert(addr, svc)) => {
+                self.named_count += 1;
+                if self.named_count == 1 {
+                    // The control plane has just started reporting
+                    // endpoints for this name; anything DNS had inserted
+                    // would otherwise linger in the `Balance` as a stale
+                    // duplicate, so queue it up for retraction.
+                    self.dns_retractions = self.dns.retract_all();
+                }
+                return Ok(Async::Ready(Change::Insert(addr, circuit_break(svc))));
+            }
+            Async::Ready(Change::Remove(addr)) => {
+                self.named_count = self.named_count.saturating_sub(1);
+                return Ok(Async::Ready(Change::Remove(addr)));
+            }
+            Async::NotReady => {}
+        }
+
+        if self.named_count > 0 {
+            // The control plane has current endpoints for this name;
+            // don't also resolve it via DNS.
+            return Ok(Async::NotReady);
+        }
+
+        let change = try_ready!(self.dns.poll());
+        let change = match change {
+            Change::Insert(addr, svc) => Change::Insert(addr, circuit_break(svc)),
+            Change::Remove(addr) => Change::Remove(addr),
+        };
+        Ok(Async::Ready(change))
+    }
+}
+
+/// Periodically re-resolves a `DnsNameAndPort` to a set of `SocketAddr`s
+/// via plain DNS, diffing the address set on each refresh so that only
+/// `Change::Insert`/`Change::Remove` for addresses that actually changed
+/// are emitted. The refresh interval is the record's TTL, clamped to
+/// `[MIN_DNS_REFRESH, MAX_DNS_REFRESH]`.
+pub struct DnsResolve<B> {
+    name: DnsNameAndPort,
+    bind: BindProtocol<B>,
+    resolver: dns::Resolver,
+    query: Option<dns::Query>,
+    /// The full address set from the most recent answer, used to diff
+    /// against the next one. This includes addresses still sitting in
+    /// `pending_inserts` that haven't been emitted as a `Change::Insert`
+    /// yet -- `emitted` is the set to consult for anything that needs to
+    /// agree with what the `Balance` actually has.
+    addrs: HashSet<SocketAddr>,
+    /// Addresses actually emitted as `Change::Insert` and not yet
+    /// retracted or removed. Kept separate from `addrs`, which is updated
+    /// as soon as an answer arrives while the `Change::Insert`s for it are
+    /// only emitted lazily off `pending_inserts` -- `retract_all` must only
+    /// return `Change::Remove`s for addresses the `Balance` actually knows
+    /// about.
+    emitted: HashSet<SocketAddr>,
+    /// Diffs computed from the most recent answer that haven't yet been
+    /// emitted as a `Change`. A single DNS answer can add and remove
+    /// several addresses at once, but `Discover::poll` can only return one
+    /// `Change` per call, so these are drained one at a time across
+    /// however many polls it takes -- `refresh_at` only gates issuing the
+    /// *next* query, not draining what the last answer already found.
+    pending_removals: Vec<SocketAddr>,
+    pending_inserts: Vec<SocketAddr>,
+    refresh_at: Instant,
+}
+
+impl<B> DnsResolve<B> {
+    fn new(name: DnsNameAndPort, bind: BindProtocol<B>, resolver: dns::Resolver) -> Self {
+        DnsResolve {
+            name,
+            bind,
+            resolver,
+            query: None,
+            addrs: HashSet::new(),
+            emitted: HashSet::new(),
+            pending_removals: Vec::new(),
+            pending_inserts: Vec::new(),
+            refresh_at: Instant::now(),
+        }
+    }
+
+    /// Retract every address this resolver has actually emitted as a
+    /// `Change::Insert`, discarding any not-yet-drained diffs, and return
+    /// them so the caller can emit the corresponding `Change::Remove`s.
+    ///
+    /// Used when a higher-priority source of endpoints (e.g. the control
+    /// plane) takes over, so these don't linger as stale duplicates. Only
+    /// `emitted` addresses are returned -- anything still queued in
+    /// `pending_inserts` was never handed to the `Balance` in the first
+    /// place, so retracting it too would emit a `Change::Remove` for a key
+    /// the `Balance` never received. `addrs` is cleared as well, so the
+    /// next answer is diffed against an empty set and every address it
+    /// carries is inserted afresh.
+    fn retract_all(&mut self) -> Vec<SocketAddr> {
+        self.pending_removals.clear();
+        self.pending_inserts.clear();
+        self.addrs.clear();
+        self.emitted.drain().collect()
+    }
+}
+
+impl<B> DnsResolve<B>
+where
+    B: tower_h2::Body + Send + 'static,
+    <B::Data as ::bytes::IntoBuf>::Buf: Send,
+{
+    fn poll(&mut self) -> Poll<Change<SocketAddr, bind::Service<B>>, BindError> {
+        loop {
+            if let Some(removed) = self.pending_removals.pop() {
+                self.emitted.remove(&removed);
+                return Ok(Async::Ready(Change::Remove(removed)));
+            }
+
+            if let Some(added) = self.pending_inserts.pop() {
+                let svc = self.bind.bind(&added.into())
+                    .map_err(|_| BindError::External { addr: added })?;
+                self.emitted.insert(added);
+                return Ok(Async::Ready(Change::Insert(added, svc)));
+            }
+
+            if self.query.is_none() {
+                if Instant::now() < self.refresh_at {
+                    return Ok(Async::NotReady);
+                }
+                self.query = Some(self.resolver.resolve_a(&self.name.host));
+            }
+
+            let answer = {
+                let query = self.query.as_mut().expect("query must be set");
+                try_ready!(query.poll().map_err(|_| BindError::Internal))
+            };
+            self.query = None;
+
+            let ttl = cmp::max(MIN_DNS_REFRESH, cmp::min(MAX_DNS_REFRESH, answer.ttl));
+            self.refresh_at = Instant::now() + ttl;
+
+            let port = self.name.port;
+            let resolved: HashSet<SocketAddr> = answer.ips.into_iter()
+                .map(|ip| SocketAddr::from((ip, port)))
+                .collect();
+
+            self.pending_removals = self.addrs.difference(&resolved).cloned().collect();
+            self.pending_inserts = resolved.difference(&self.addrs).cloned().collect();
+            self.addrs = resolved;
+
+            // Loop back around to drain the diffs just computed above.
+        }
+    }
+}
+
+/// A single endpoint discovered from the `SO_ORIGINAL_DST` socket option.
+///
+/// Unlike `Fallback`, this never hears about address changes from the
+/// control plane, so it watches its own `CircuitBreaker` and evicts
+/// itself from the `Balance` (via `Change::Remove`) when the circuit
+/// opens, re-admitting it (via `Change::Insert`) once the breaker has
+/// cooled down enough to try a probe request.
+pub struct ImplicitOriginalDst<B> {
+    addr: SocketAddr,
+    bind: BindProtocol<B>,
+    breaker: Breaker,
+    inserted: bool,
+}
+
+impl<B> ImplicitOriginalDst<B>
+where
+    B: tower_h2::Body + Send + 'static,
+    <B::Data as ::bytes::IntoBuf>::Buf: Send,
+{
+    fn poll(&mut self) -> Poll<Change<SocketAddr, CircuitBreaker<bind::Service<B>>>, BindError> {
+        self.breaker.poll_reopen();
+        let is_open = self.breaker.is_open();
+
+        if is_open {
+            if self.inserted {
+                self.inserted = false;
+                return Ok(Async::Ready(Change::Remove(self.addr)));
+            }
+            return Ok(Async::NotReady);
+        }
+
+        if !self.inserted {
+            let svc = self.bind.bind(&self.addr.into())
+                .map_err(|_| BindError::External { addr: self.addr })?;
+            self.inserted = true;
+            let svc = CircuitBreaker::new(svc, self.breaker.clone());
+            return Ok(Async::Ready(Change::Insert(self.addr, svc)));
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+/// Shared circuit-breaker state for a single endpoint's connection, plus
+/// the timer that reopens it. See `BreakerCell::poll_reopen`.
+type Breaker = Rc<BreakerCell>;
+
+/// After `FAILURE_THRESHOLD` consecutive failures, the circuit opens for
+/// `MIN_OPEN_DURATION`, doubling (up to `MAX_OPEN_DURATION`) each time a
+/// half-open probe also fails.
+const FAILURE_THRESHOLD: u32 = 3;
+const MIN_OPEN_DURATION: Duration = Duration::from_secs(1);
+const MAX_OPEN_DURATION: Duration = Duration::from_secs(60);
+
+#[derive(Copy, Clone, Debug)]
+enum BreakerState {
+    /// Requests are admitted normally; `consecutive_failures` counts
+    /// unbroken `poll_ready`/connect failures since the last success.
+    Closed { consecutive_failures: u32 },
+    /// The circuit has tripped: `poll_ready` fails fast until `until`.
+    Open { until: Instant, reopen_after: Duration },
+    /// The cooldown has elapsed; the next request is admitted as a
+    /// single probe while further requests continue to fail fast.
+    HalfOpen { reopen_after: Duration },
+    /// A probe request is in flight.
+    Probing { reopen_after: Duration },
+}
+
+impl BreakerState {
+    fn closed() -> Self {
+        BreakerState::Closed { consecutive_failures: 0 }
+    }
+
+    fn is_open(&self) -> bool {
+        match *self {
+            BreakerState::Open { .. } => true,
+            _ => false,
+        }
+    }
+
+    fn record_success(&mut self) {
+        *self = BreakerState::closed();
+    }
+
+    fn record_failure(&mut self) {
+        *self = match *self {
+            BreakerState::Closed { consecutive_failures } => {
+                if consecutive_failures + 1 < FAILURE_THRESHOLD {
+                    BreakerState::Closed { consecutive_failures: consecutive_failures + 1 }
                 } else {
-                    Ok(Async::NotReady)
+                    BreakerState::Open {
+                        until: Instant::now() + MIN_OPEN_DURATION,
+                        reopen_after: MIN_OPEN_DURATION,
+                    }
+                }
+            }
+            BreakerState::Probing { reopen_after } => {
+                let reopen_after = cmp::min(reopen_after * 2, MAX_OPEN_DURATION);
+                BreakerState::Open {
+                    until: Instant::now() + reopen_after,
+                    reopen_after,
+                }
+            }
+            BreakerState::Open { reopen_after, .. } |
+            BreakerState::HalfOpen { reopen_after } => {
+                BreakerState::Open {
+                    until: Instant::now() + reopen_after,
+                    reopen_after,
                 }
             }
+        };
+    }
+}
+
+/// Wraps `BreakerState` together with the `Delay` that reopens it.
+///
+/// `poll_reopen` used to just compare `Instant::now()` against the open
+/// deadline: if nothing repolled the breaker after that deadline passed,
+/// it would simply stay open forever, since nothing had registered this
+/// task to be woken at `until`. Driving the reopen off a real `Delay`
+/// means polling it while open parks this task with the timer, so it
+/// gets woken and re-admits the endpoint on its own once the cooldown
+/// elapses -- no unrelated traffic required.
+struct BreakerCell {
+    state: RefCell<BreakerState>,
+    delay: RefCell<Option<Delay>>,
+}
+
+impl BreakerCell {
+    fn closed() -> Breaker {
+        Rc::new(BreakerCell {
+            state: RefCell::new(BreakerState::closed()),
+            delay: RefCell::new(None),
+        })
+    }
+
+    /// If `Open`, polls (arming if necessary) a `Delay` for the cooldown
+    /// deadline, transitioning to `HalfOpen` once it fires.
+    fn poll_reopen(&self) {
+        let until = match *self.state.borrow() {
+            BreakerState::Open { until, .. } => until,
+            _ => {
+                *self.delay.borrow_mut() = None;
+                return;
+            }
+        };
+
+        let elapsed = {
+            let mut delay = self.delay.borrow_mut();
+            let delay = delay.get_or_insert_with(|| Delay::new(until));
+            match delay.poll() {
+                Ok(Async::Ready(())) => true,
+                Ok(Async::NotReady) => false,
+                // A wedged timer shouldn't wedge the breaker open forever;
+                // treat an error the same as the deadline having elapsed.
+                Err(_) => true,
+            }
+        };
+
+        if elapsed {
+            *self.delay.borrow_mut() = None;
+            let mut state = self.state.borrow_mut();
+            if let BreakerState::Open { reopen_after, .. } = *state {
+                *state = BreakerState::HalfOpen { reopen_after };
+            }
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.state.borrow().is_open()
+    }
+
+    fn record_success(&self) {
+        self.state.borrow_mut().record_success();
+        *self.delay.borrow_mut() = None;
+    }
+
+    /// Re-arms the delay (by clearing it, so the next `poll_reopen` builds
+    /// a fresh one) since a new failure moves `until` forward.
+    fn record_failure(&self) {
+        self.state.borrow_mut().record_failure();
+        *self.delay.borrow_mut() = None;
+    }
+
+    fn is_probing(&self) -> bool {
+        match *self.state.borrow() {
+            BreakerState::Probing { .. } => true,
+            _ => false,
+        }
+    }
+
+    fn start_probe(&self) {
+        let mut state = self.state.borrow_mut();
+        if let BreakerState::HalfOpen { reopen_after } = *state {
+            *state = BreakerState::Probing { reopen_after };
+        }
+    }
+}
+
+/// Wraps an endpoint's `bind::Service`, failing `poll_ready` fast while
+/// the circuit is open rather than letting a dead endpoint's errors
+/// destroy requests buffered ahead of it.
+///
+/// See `ImplicitOriginalDst` for how the breaker's state is also used
+/// to evict and re-admit the endpoint in the `Balance`.
+pub struct CircuitBreaker<S> {
+    inner: S,
+    state: Breaker,
+}
+
+impl<S> CircuitBreaker<S> {
+    fn new(inner: S, state: Breaker) -> Self {
+        CircuitBreaker { inner, state }
+    }
+}
+
+impl<S> tower::Service for CircuitBreaker<S>
+where
+    S: tower::Service,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = CircuitBreakerFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.state.poll_reopen();
+        if self.state.is_open() || self.state.is_probing() {
+            return Ok(Async::NotReady);
+        }
+
+        match self.inner.poll_ready() {
+            Ok(ready) => Ok(ready),
+            Err(e) => {
+                self.state.record_failure();
+                Err(e)
+            }
+        }
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        self.state.start_probe();
+        CircuitBreakerFuture {
+            inner: self.inner.call(req),
+            state: self.state.clone(),
+        }
+    }
+}
+
+/// The `CircuitBreaker`'s response future, which records the outcome of
+/// the request against the shared breaker state when it resolves.
+pub struct CircuitBreakerFuture<F> {
+    inner: F,
+    state: Breaker,
+}
+
+impl<F> Future for CircuitBreakerFuture<F>
+where
+    F: Future,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.inner.poll() {
+            Ok(Async::Ready(rsp)) => {
+                self.state.record_success();
+                Ok(Async::Ready(rsp))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => {
+                self.state.record_failure();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A token-bucket admission-control layer: `maxrate` tokens refill per
+/// second, up to a `burst` capacity, and each admitted request consumes
+/// one token. `tokens` is allowed to float below zero when admission
+/// outpaces refill -- that shortfall is the "debt" the high/low
+/// watermarks bound, so it sheds in a hysteresis band instead of
+/// flapping admit/reject every poll. (Were `tokens` clamped at zero like
+/// a textbook token bucket, debt would be capped at `maxrate` too, and a
+/// `high` watermark at or above `maxrate` could never be crossed.)
+pub struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+    maxrate: f64,
+    burst: f64,
+    high: f64,
+    low: f64,
+    shedding: bool,
+}
+
+impl TokenBucket {
+    fn new(maxrate: f64, burst: f64, watermark_offset: f64) -> Self {
+        TokenBucket {
+            tokens: burst,
+            last_refill: Instant::now(),
+            maxrate,
+            burst,
+            high: maxrate,
+            low: (maxrate - watermark_offset).max(0.0),
+            shedding: false,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed_secs * self.maxrate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    /// How far `tokens` has dipped below zero: the number of admitted
+    /// requests that have outrun the configured refill rate. Unlike a
+    /// textbook token bucket, `tokens` is never clamped at zero, so this
+    /// can actually grow past `high` rather than saturating at `maxrate`.
+    fn debt(&self) -> f64 {
+        (-self.tokens).max(0.0)
+    }
+
+    /// Refills the bucket, updates the shedding hysteresis, and returns
+    /// whether a request may be admitted right now. Does not itself spend a
+    /// token -- callers that decide to admit must call `consume` once the
+    /// request is actually dispatched, so polling alone never drains the
+    /// bucket.
+    fn poll_admit(&mut self) -> bool {
+        self.refill();
+
+        if self.shedding {
+            if self.debt() < self.low {
+                self.shedding = false;
+            }
+        } else if self.debt() > self.high {
+            self.shedding = true;
         }
+
+        !self.shedding
+    }
+
+    /// Spends one token. Only call this once a request `poll_admit` admitted
+    /// is actually being dispatched -- polling admission repeatedly (e.g.
+    /// while the inner service isn't ready yet) must not by itself spend
+    /// tokens that no request was ever sent with.
+    fn consume(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenBucket;
+
+    /// Drives a `TokenBucket` far enough into debt to trip the high
+    /// watermark, then confirms it keeps shedding until debt has drained
+    /// back below the low watermark (not merely below the high one).
+    #[test]
+    fn token_bucket_sheds_in_watermark_band() {
+        // `high` == `maxrate` == 5.0, `low` == `maxrate - watermark_offset`
+        // == 1.0. `maxrate` is small enough that `refill()`'s passive
+        // top-up over the life of this test is negligible next to the
+        // debt set directly below.
+        let mut bucket = TokenBucket::new(5.0, 10.0, /* watermark_offset */ 4.0);
+
+        assert!(bucket.poll_admit(), "requests are admitted with no debt");
+
+        bucket.tokens = -6.0; // debt == 6.0, past the high watermark.
+        assert!(!bucket.poll_admit(), "admission should shed once debt exceeds the high watermark");
+        assert!(bucket.shedding);
+
+        bucket.tokens = -3.0; // debt == 3.0, inside the hysteresis band.
+        assert!(!bucket.poll_admit(), "shedding should persist between low and high");
+        assert!(bucket.shedding);
+
+        bucket.tokens = 0.0; // debt == 0.0, below the low watermark.
+        assert!(bucket.poll_admit(), "debt below the low watermark should clear shedding");
+        assert!(!bucket.shedding);
+    }
+
+    /// `poll_admit` must not itself spend supply -- only `consume` may,
+    /// once the admitted request is actually dispatched -- otherwise a
+    /// caller that polls repeatedly while the inner service stays
+    /// `NotReady` drains tokens out from under a request that never went
+    /// through.
+    #[test]
+    fn token_bucket_poll_admit_does_not_spend_tokens() {
+        let mut bucket = TokenBucket::new(5.0, 10.0, 4.0);
+
+        for _ in 0..5 {
+            assert!(bucket.poll_admit());
+        }
+        assert_eq!(bucket.tokens, 10.0, "poll_admit alone must not spend tokens");
+
+        bucket.consume();
+        assert_eq!(bucket.tokens, 9.0, "consume spends exactly one token");
+    }
+}
+
+/// Wraps a service with the `TokenBucket` admission control above,
+/// failing `poll_ready` fast (rather than queueing unboundedly) while
+/// shedding, so the caller is backpressured before requests reach the
+/// inner `Buffer`.
+pub struct RateLimit<S> {
+    inner: S,
+    bucket: Rc<RefCell<TokenBucket>>,
+}
+
+impl<S> RateLimit<S> {
+    fn new(inner: S, bucket: TokenBucket) -> Self {
+        RateLimit {
+            inner,
+            bucket: Rc::new(RefCell::new(bucket)),
+        }
+    }
+}
+
+impl<S> tower::Service for RateLimit<S>
+where
+    S: tower::Service,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        if !self.bucket.borrow_mut().poll_admit() {
+            return Ok(Async::NotReady);
+        }
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        // Tokens are spent here, once a request is actually dispatched --
+        // not in `poll_ready`, which may be polled many times (e.g. while
+        // the inner `Timeout<Buffer<..>>` is still `NotReady`) before a
+        // single request is ever sent.
+        self.bucket.borrow_mut().consume();
+        self.inner.call(req)
     }
 }
+
+/// A `Balance` whose `choose` policy is selected at construction time by
+/// `BalancerPolicy`, so a single router `Key` can be bound to whichever
+/// policy an operator configured on `Outbound`.
+pub enum Balancer<B> {
+    PowerOfTwoChoices(Balance<
+        load::WithPeakEwma<Discovery<B>, PendingUntilFirstData>,
+        choose::PowerOfTwoChoices,
+    >),
+    LeastLoaded(Balance<
+        load::WithPeakEwma<Discovery<B>, PendingUntilFirstData>,
+        LeastLoaded,
+    >),
+    RoundRobin(Balance<
+        load::WithPeakEwma<Discovery<B>, PendingUntilFirstData>,
+        choose::RoundRobin,
+    >),
+}
+
+// The three variants share the same `Discover` (`load::WithPeakEwma<Discovery<B>,
+// PendingUntilFirstData>`) and differ only in their `choose` policy, which
+// `Balance`'s `Request`/`Response`/`Error`/`Future` types don't depend on. So
+// it's safe to read them off of one variant (`PowerOfTwoChoices`, arbitrarily)
+// and know the others agree.
+type BalanceRequest<B> = <Balance<
+    load::WithPeakEwma<Discovery<B>, PendingUntilFirstData>,
+    choose::PowerOfTwoChoices,
+> as tower::Service>::Request;
+type BalanceResponse<B> = <Balance<
+    load::WithPeakEwma<Discovery<B>, PendingUntilFirstData>,
+    choose::PowerOfTwoChoices,
+> as tower::Service>::Response;
+type BalanceError<B> = <Balance<
+    load::WithPeakEwma<Discovery<B>, PendingUntilFirstData>,
+    choose::PowerOfTwoChoices,
+> as tower::Service>::Error;
+type BalanceFuture<B> = <Balance<
+    load::WithPeakEwma<Discovery<B>, PendingUntilFirstData>,
+    choose::PowerOfTwoChoices,
+> as tower::Service>::Future;
+
+impl<B> tower::Service for Balancer<B>
+where
+    B: tower_h2::Body + Send + 'static,
+    <B::Data as ::bytes::IntoBuf>::Buf: Send,
+{
+    type Request = BalanceRequest<B>;
+    type Response = BalanceResponse<B>;
+    type Error = BalanceError<B>;
+    type Future = BalanceFuture<B>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        match *self {
+            Balancer::PowerOfTwoChoices(ref mut b) => b.poll_ready(),
+            Balancer::LeastLoaded(ref mut b) => b.poll_ready(),
+            Balancer::RoundRobin(ref mut b) => b.poll_ready(),
+        }
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        match *self {
+            Balancer::PowerOfTwoChoices(ref mut b) => b.call(req),
+            Balancer::LeastLoaded(ref mut b) => b.call(req),
+            Balancer::RoundRobin(ref mut b) => b.call(req),
+        }
+    }
+}
+
+/// A `choose::Choose` policy that scans every ready replica and picks
+/// the one with the lowest current `Load`, rather than sampling two at
+/// random like `choose::PowerOfTwoChoices`. This is more predictable on
+/// workloads where P2C's randomness can land on a momentarily-hot
+/// replica, at the cost of an O(n) scan per choice.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LeastLoaded {
+    _p: (),
+}
+
+impl<K, L> choose::Choose<K, L> for LeastLoaded
+where
+    L: load::Load,
+    L::Metric: PartialOrd,
+{
+    // `Choose::choose` returns the *index* of the selected replica within
+    // `replicas`, not its key -- `Balance` looks the key back up itself.
+    fn choose(&mut self, replicas: choose::Replicas<K, L>) -> usize {
+        replicas.into_iter()
+            .enumerate()
+            .min_by(|a, b| {
+                (a.1).1.load().partial_cmp(&(b.1).1.load())
+                    .unwrap_or(::std::cmp::Ordering::Equal)
+            })
+            .map(|(index, _)| index)
+            .expect("choose must be called with at least one replica")
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum BindError {
     External { addr: SocketAddr },
@@ -1,62 +1,104 @@
 #![deny(missing_docs)]
 use std::{ops, slice, u32};
 use std::default::Default;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::time::Duration;
 
-/// The number of buckets in a  latency histogram.
-pub const NUM_BUCKETS: usize = 26;
-
-/// The maximum value (inclusive) for each latency bucket.
-pub const BUCKET_MAX_VALUES: [Latency; NUM_BUCKETS] = [
-    // The controller telemetry server creates 5 sets of 5 linear buckets
-    // each:
-    // TODO: it would be nice if we didn't have to hard-code each
-    //       individual bucket and could use Rust ranges or something.
-    //       However, because we're using a raw fixed size array rather
-    //       than a vector (as we don't ever expect to grow this array
-    //       and thus don't _need_ a vector) we can't concatenate it
-    //       from smaller arrays, making it difficult to construct
-    //       programmatically...
-    // in the controller:
-    // prometheus.LinearBuckets(1, 1, 5),
-    Latency(1),
-    Latency(2),
-    Latency(3),
-    Latency(4),
-    Latency(5),
-    // prometheus.LinearBuckets(10, 10, 5),
-    Latency(10),
-    Latency(20),
-    Latency(30),
-    Latency(40),
-    Latency(50),
-    // prometheus.LinearBuckets(100, 100, 5),
-    Latency(100),
-    Latency(200),
-    Latency(300),
-    Latency(400),
-    Latency(500),
-    // prometheus.LinearBuckets(1000, 1000, 5),
-    Latency(1_000),
-    Latency(2_000),
-    Latency(3_000),
-    Latency(4_000),
-    Latency(0_000),
-    // prometheus.LinearBuckets(10000, 10000, 5),
-    Latency(10_000),
-    Latency(20_000),
-    Latency(30_000),
-    Latency(40_000),
-    Latency(50_000),
-    // Prometheus implicitly creates a max bucket for everything that
-    // falls outside of the highest-valued bucket, but we need to
-    // create it explicitly.
-    Latency(u32::MAX),
-];
+/// Bit-shift exponents describing a log-linear bucket layout.
+///
+/// The smallest bucket width is `2^m`; every value below `2^r` falls into a
+/// uniformly-`2^m`-wide linear bucket; values at or above `2^r` are bucketed
+/// by power-of-two band, with each band split into `2^(r - m - 1)`
+/// sub-buckets so the relative error stays bounded as magnitude grows.
+/// Values at or above `2^n` all land in a single terminal bucket. This is
+/// the scheme Twitter's `Metrics` library (and HDR histogram) use to cover a
+/// wide dynamic range without a wall of hand-written bucket boundaries.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Resolution {
+    m: u32,
+    r: u32,
+    n: u32,
+}
+
+/// Approximates the cardinality of the hand-written 26-bucket layout the
+/// controller's telemetry server previously used: 16ms linear resolution
+/// up to 64ms, then log-linear (2 sub-buckets per power-of-two octave,
+/// i.e. ~50% relative error) out to ~65 seconds. This yields
+/// `2^(r-m) + (n-r)*2^(r-m-1) + 1` = `4 + 10*2 + 1` = 25 buckets, in the
+/// same ballpark as the old array rather than the thousands a
+/// finer-grained layout would export per route.
+const DEFAULT_RESOLUTION: Resolution = Resolution { m: 4, r: 6, n: 16 };
+
+/// The number of buckets a default-constructed `Histogram` or
+/// `AtomicHistogram` has, derived from `DEFAULT_RESOLUTION` the same way
+/// `Resolution::bucket_bounds` derives the bounds themselves: `2^(r - m)`
+/// linear buckets, `(n - r) * 2^(r - m - 1)` log-linear buckets, plus the
+/// terminal bucket. Kept as a `const` (rather than only a `num_buckets()`
+/// method) because Prometheus exposition code needs the bucket count
+/// before it has a histogram in hand to call that method on.
+pub const NUM_BUCKETS: usize = (1 << (DEFAULT_RESOLUTION.r - DEFAULT_RESOLUTION.m))
+    + (DEFAULT_RESOLUTION.n - DEFAULT_RESOLUTION.r) as usize
+        * (1 << (DEFAULT_RESOLUTION.r - DEFAULT_RESOLUTION.m - 1))
+    + 1;
+
+impl Resolution {
+    /// Generate the ascending, inclusive bucket maximums for this layout.
+    fn bucket_bounds(&self) -> Vec<Latency> {
+        let Resolution { m, r, n } = *self;
+        debug_assert!(m < r, "linear range must be wider than one bucket");
+        debug_assert!(r < n, "log-linear range must extend past the linear range");
+
+        let mut bounds = Vec::new();
+
+        // Linear region: every value below `2^r` is bucketed by a plain
+        // right-shift, giving `2^(r - m)` buckets of width `2^m`.
+        let linear_buckets = 1u64 << (r - m);
+        for i in 0..linear_buckets {
+            bounds.push(Latency((((i + 1) << m) - 1) as u32));
+        }
+
+        // Log-linear region: each power-of-two band `[2^b, 2^(b+1))` for
+        // `b` in `r..n` is split into `2^(r - m - 1)` equal sub-buckets.
+        let sub_buckets = 1u64 << (r - m - 1);
+        for b in r..n {
+            let band_start = 1u64 << b;
+            let band_width = band_start / sub_buckets;
+            for sub in 0..sub_buckets {
+                let max = band_start + (sub + 1) * band_width - 1;
+                if max >= u64::from(u32::MAX) {
+                    break;
+                }
+                bounds.push(Latency(max as u32));
+            }
+        }
+
+        // Prometheus implicitly creates a max bucket for everything that
+        // falls outside of the highest-valued bucket, but we need to
+        // create it explicitly.
+        bounds.push(Latency(u32::MAX));
+        bounds
+    }
+}
 
 /// A series of latency values and counts.
 #[derive(Debug)]
-pub struct Histogram([u32; NUM_BUCKETS]);
+pub struct Histogram {
+    /// The maximum value (inclusive) of each bucket, in ascending order.
+    bucket_bounds: Vec<Latency>,
+
+    buckets: Vec<u32>,
+
+    /// The total of all observed latencies, in milliseconds.
+    ///
+    /// This is tracked separately from `buckets` because a faithful
+    /// Prometheus histogram exposition needs an exact `_sum`, and because
+    /// it lets `mean()` compute a true average rather than one quantized
+    /// to bucket boundaries.
+    sum: u64,
+
+    /// The total number of observations. Exposed as `_count`.
+    count: u64,
+}
 
 /// A latency in milliseconds.
 #[derive(Debug, Default, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Hash)]
@@ -73,22 +115,170 @@ impl Histogram {
         I: Into<Latency>,
     {
         let measurement = measurement.into();
-        let i = BUCKET_MAX_VALUES.iter()
+        let i = self.bucket_bounds.iter()
             .position(|max| &measurement <= max)
             .expect("latency value greater than u32::MAX; this shouldn't be \
                      possible.");
-        self.0[i] += 1;
+        self.buckets[i] += 1;
+        self.sum += u64::from(measurement.0);
+        self.count += 1;
+    }
+
+    /// The total of all observed latencies, in milliseconds.
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// The total number of observations.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The mean of all observed latencies, in milliseconds.
+    ///
+    /// Returns `0.0` if there have been no observations, rather than NaN.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+
+    /// The number of buckets in this histogram.
+    pub fn num_buckets(&self) -> usize {
+        self.bucket_bounds.len()
+    }
+
+    /// The maximum value (inclusive) of each bucket, in ascending order,
+    /// in the same order as the counts yielded by iterating `&self`.
+    ///
+    /// Needed by Prometheus exposition, which labels each bucket with its
+    /// own `le=<upper bound>` value.
+    pub fn bucket_bounds(&self) -> &[Latency] {
+        &self.bucket_bounds
+    }
+
+    /// Approximate the latency value at quantile `q` (in `[0.0, 1.0]`).
+    ///
+    /// Walks the cumulative bucket counts to find the bucket containing the
+    /// `q * count`-th observation, then linearly interpolates within that
+    /// bucket between its lower and upper bound. The estimate is only as
+    /// precise as the bucket that contains it, so resolution near a given
+    /// quantile depends on bucket granularity — see
+    /// `Histogram::with_resolution` for a finer-grained layout.
+    pub fn quantile(&self, q: f64) -> Latency {
+        self.percentiles(&[q])[0]
+    }
+
+    /// Approximate several quantiles at once in a single pass over the
+    /// buckets, cheaper than calling `quantile` once per value.
+    pub fn percentiles(&self, qs: &[f64]) -> Vec<Latency> {
+        if self.count == 0 {
+            return vec![Latency(0); qs.len()];
+        }
+
+        let mut targets: Vec<(usize, u64)> = qs.iter()
+            .enumerate()
+            .map(|(idx, &q)| (idx, (q * self.count as f64).ceil().max(0.0) as u64))
+            .collect();
+        targets.sort_by_key(|&(_, target)| target);
+        let mut targets = targets.into_iter().peekable();
+
+        let mut results = vec![Latency(u32::MAX); qs.len()];
+        let mut cumulative = 0u64;
+
+        for (i, &bucket_count) in self.buckets.iter().enumerate() {
+            let bucket_cumulative = cumulative + u64::from(bucket_count);
+            while let Some(&(idx, target)) = targets.peek() {
+                if target > bucket_cumulative {
+                    break;
+                }
+                let rank_in_bucket = target.saturating_sub(cumulative);
+                results[idx] = self.bucket_estimate(i, rank_in_bucket, bucket_count);
+                targets.next();
+            }
+            cumulative = bucket_cumulative;
+        }
+
+        results
+    }
+
+    /// Estimate a latency value within bucket `i`, given the rank (within
+    /// that bucket alone) of the observation being estimated.
+    fn bucket_estimate(&self, i: usize, rank_in_bucket: u64, bucket_count: u32) -> Latency {
+        let upper = self.bucket_bounds[i].0;
+        let lower = if i == 0 {
+            0
+        } else {
+            self.bucket_bounds[i - 1].0 + 1
+        };
+
+        // The final bucket's nominal upper bound is `u32::MAX`; rather than
+        // interpolating out to that, treat its estimate as its lower bound.
+        if i + 1 == self.bucket_bounds.len() {
+            return Latency(lower);
+        }
+
+        if bucket_count == 0 || upper <= lower {
+            return Latency(upper);
+        }
+
+        let frac = rank_in_bucket as f64 / f64::from(bucket_count);
+        let interpolated = f64::from(lower) + frac * f64::from(upper - lower);
+        Latency(interpolated.round() as u32)
     }
 
-    /// Construct a new, empty `Histogram`.
+    /// Merge another histogram's counts into this one, summing bucket
+    /// counts and totals element-wise.
     ///
-    /// The buckets in this `Histogram` should mimic the Prometheus buckets
-    /// created by the Conduit controller's telemetry server, but with max
-    /// values one order of magnitude higher. This is because we're recording
-    /// latencies in tenths of a millisecond, but truncating these observations
-    /// to millisecond resolution.
+    /// Useful for reducing a `Vec<Histogram>` collected from worker
+    /// threads (or separate time windows) into a single scrape-ready
+    /// histogram without re-observing every sample.
+    ///
+    /// In debug builds, asserts that `other` shares this histogram's
+    /// bucket layout; merging histograms built with different
+    /// resolutions would silently misattribute counts to the wrong
+    /// bucket boundaries.
+    pub fn merge(&mut self, other: &Histogram) {
+        debug_assert_eq!(
+            self.bucket_bounds, other.bucket_bounds,
+            "cannot merge histograms with different bucket layouts",
+        );
+        for (bucket, &other_bucket) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += other_bucket;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+
+    /// Construct a new, empty `Histogram`, using a layout that approximates
+    /// the bucket boundaries the Conduit controller's telemetry server
+    /// previously used.
     pub fn new() -> Self {
-        Histogram([0; NUM_BUCKETS])
+        let Resolution { m, r, n } = DEFAULT_RESOLUTION;
+        Self::with_resolution(m, r, n)
+    }
+
+    /// Construct a new, empty `Histogram` with a log-linear bucket layout.
+    ///
+    /// `m`, `r`, and `n` are bit-shift exponents: the smallest bucket is
+    /// `2^m` wide, every value below `2^r` falls into a uniformly `2^m`-wide
+    /// linear bucket, and values at or above `2^n` all land in a single
+    /// terminal bucket. Between `2^r` and `2^n`, each power-of-two band is
+    /// split into `2^(r - m - 1)` sub-buckets, so the relative error of an
+    /// estimate drawn from a bucket stays bounded at roughly
+    /// `2^-(r - m - 1)` regardless of magnitude.
+    pub fn with_resolution(m: u32, r: u32, n: u32) -> Self {
+        let resolution = Resolution { m, r, n };
+        let bucket_bounds = resolution.bucket_bounds();
+        let buckets = vec![0; bucket_bounds.len()];
+        Histogram {
+            bucket_bounds,
+            buckets,
+            sum: 0,
+            count: 0,
+        }
     }
 
 }
@@ -104,13 +294,21 @@ where
 
 }
 
+impl<'a> ops::AddAssign<&'a Histogram> for Histogram {
+    #[inline]
+    fn add_assign(&mut self, other: &'a Histogram) {
+        self.merge(other)
+    }
+
+}
+
 
 impl<'a> IntoIterator for &'a Histogram {
     type Item = &'a u32;
     type IntoIter = slice::Iter<'a, u32>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.0.iter()
+        self.buckets.iter()
     }
 
 }
@@ -123,6 +321,159 @@ impl Default for Histogram {
     }
 }
 
+// ===== impl AtomicHistogram =====
+
+/// A lock-free variant of `Histogram` that can be observed from multiple
+/// threads concurrently through a shared (`&self`) reference.
+///
+/// Each bucket counter, along with `sum` and `count`, is stored as an
+/// atomic and updated with `fetch_add` under `Ordering::Relaxed`: observers
+/// only need the increments to happen somewhere, not to be ordered with
+/// respect to any other memory access, so a mutex around every measurement
+/// would be needless contention on the hot path.
+#[derive(Debug)]
+pub struct AtomicHistogram {
+    bucket_bounds: Vec<Latency>,
+    buckets: Vec<AtomicU32>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl AtomicHistogram {
+
+    /// Observe a measurement.
+    ///
+    /// Unlike `Histogram::observe`, this takes `&self`, so it may be called
+    /// from many threads sharing the same histogram (typically behind an
+    /// `Arc`) without any external synchronization.
+    pub fn observe<I>(&self, measurement: I)
+    where
+        I: Into<Latency>,
+    {
+        let measurement = measurement.into();
+        let i = self.bucket_bounds.iter()
+            .position(|max| &measurement <= max)
+            .expect("latency value greater than u32::MAX; this shouldn't be \
+                     possible.");
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(u64::from(measurement.0), Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The total of all observed latencies, in milliseconds.
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+
+    /// The total number of observations.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The mean of all observed latencies, in milliseconds.
+    ///
+    /// Returns `0.0` if there have been no observations, rather than NaN.
+    pub fn mean(&self) -> f64 {
+        let count = self.count();
+        if count == 0 {
+            0.0
+        } else {
+            self.sum() as f64 / count as f64
+        }
+    }
+
+    /// The number of buckets in this histogram.
+    pub fn num_buckets(&self) -> usize {
+        self.bucket_bounds.len()
+    }
+
+    /// Construct a new, empty `AtomicHistogram`, using the same default
+    /// bucket layout as `Histogram::new`.
+    pub fn new() -> Self {
+        let Resolution { m, r, n } = DEFAULT_RESOLUTION;
+        Self::with_resolution(m, r, n)
+    }
+
+    /// Construct a new, empty `AtomicHistogram` with a log-linear bucket
+    /// layout. See `Histogram::with_resolution` for what `m`, `r`, and `n`
+    /// mean.
+    pub fn with_resolution(m: u32, r: u32, n: u32) -> Self {
+        let resolution = Resolution { m, r, n };
+        let bucket_bounds = resolution.bucket_bounds();
+        let buckets = bucket_bounds.iter().map(|_| AtomicU32::new(0)).collect();
+        AtomicHistogram {
+            bucket_bounds,
+            buckets,
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Read this histogram's counters into a plain, point-in-time
+    /// `Histogram` snapshot, cheap enough to call on every scrape.
+    ///
+    /// Because the individual counters are read one at a time, a snapshot
+    /// taken concurrently with observations may not be perfectly
+    /// consistent (a handful of buckets may reflect slightly different
+    /// moments), but `sum` and `count` are each exact as of the instant
+    /// they're read.
+    pub fn snapshot(&self) -> Histogram {
+        Histogram {
+            bucket_bounds: self.bucket_bounds.clone(),
+            buckets: self.buckets.iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            sum: self.sum(),
+            count: self.count(),
+        }
+    }
+
+}
+
+impl<I> ops::AddAssign<I> for AtomicHistogram
+where
+    I: Into<Latency>
+{
+    #[inline]
+    fn add_assign(&mut self, measurement: I) {
+        self.observe(measurement)
+    }
+
+}
+
+/// An iterator over the bucket counts of an `AtomicHistogram`.
+#[derive(Debug)]
+pub struct AtomicBuckets<'a>(slice::Iter<'a, AtomicU32>);
+
+impl<'a> Iterator for AtomicBuckets<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        self.0.next().map(|bucket| bucket.load(Ordering::Relaxed))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<'a> IntoIterator for &'a AtomicHistogram {
+    type Item = u32;
+    type IntoIter = AtomicBuckets<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        AtomicBuckets(self.buckets.iter())
+    }
+
+}
+
+impl Default for AtomicHistogram {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // ===== impl Latency =====
 
 const SEC_TO_MS: u32 = 1_000;
@@ -173,4 +524,67 @@ impl Into<u32> for Latency {
     fn into(self) -> u32 {
         self.0
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Histogram, AtomicHistogram, NUM_BUCKETS};
+
+    /// `NUM_BUCKETS` must agree with what `DEFAULT_RESOLUTION` actually
+    /// generates, for both histogram flavors that use it.
+    #[test]
+    fn num_buckets_matches_default_resolution() {
+        assert_eq!(Histogram::new().num_buckets(), NUM_BUCKETS);
+        assert_eq!(AtomicHistogram::new().num_buckets(), NUM_BUCKETS);
+    }
+
+    /// Exercises `Resolution::bucket_bounds`' linear-region, log-linear
+    /// region, and terminal-bucket arithmetic against a small, easy to
+    /// hand-check layout: `m=0, r=2, n=4` should yield `2^(r-m) = 4` linear
+    /// buckets of width 1 (`[0, 1, 2, 3]`), then `(n - r) * 2^(r-m-1) = 4`
+    /// log-linear buckets split evenly across the `[4, 8)` and `[8, 16)`
+    /// bands, then one terminal bucket -- 9 bounds in ascending order,
+    /// each one greater than the last.
+    #[test]
+    fn bucket_bounds_linear_and_log_linear_regions() {
+        let histogram = Histogram::with_resolution(0, 2, 4);
+        let bounds: Vec<u32> = histogram.bucket_bounds().iter().map(|&b| b.into()).collect();
+
+        assert_eq!(bounds, vec![0, 1, 2, 3, 5, 7, 11, 15, u32::MAX]);
+        assert_eq!(histogram.num_buckets(), 9);
+        for pair in bounds.windows(2) {
+            assert!(pair[0] < pair[1], "bucket bounds must be strictly ascending");
+        }
+    }
+
+    /// Observes a known uniform distribution and checks that the
+    /// estimated p50/p99 land within the bucket resolution's own error
+    /// bound of the true values, catching off-by-one errors in
+    /// `bucket_estimate`'s `lower`/`upper` handling that a looser assertion
+    /// could miss. No observation reaches the terminal bucket here.
+    #[test]
+    fn percentiles_of_uniform_distribution() {
+        // `m=0, r=10, n=20`: linear up to 1023ms, then log-linear with
+        // 512 sub-buckets per octave (relative error well under 1%) out
+        // to ~1024 seconds -- fine grained enough to tightly bound a
+        // uniform distribution's known quantiles.
+        let mut histogram = Histogram::with_resolution(0, 10, 20);
+        for ms in 1..=1000u32 {
+            histogram += ms;
+        }
+
+        let estimates = histogram.percentiles(&[0.5, 0.99]);
+        let p50: u32 = estimates[0].into();
+        let p99: u32 = estimates[1].into();
+
+        assert!(
+            p50 >= 490 && p50 <= 510,
+            "p50 of 1..=1000 should be close to 500, got {}", p50,
+        );
+        assert!(
+            p99 >= 980 && p99 <= 1000,
+            "p99 of 1..=1000 should be close to 990, got {}", p99,
+        );
+        assert_eq!(histogram.quantile(0.5), estimates[0]);
+    }
 }
\ No newline at end of file
@@ -9,9 +9,23 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use self::futures::sync::{mpsc, oneshot};
 use self::tokio_core::net::TcpStream;
 
+use self::rustls;
+use self::tokio_rustls;
+use self::tokio_rustls::{ClientConfigExt, ServerConfigExt};
+use self::rustls::Session;
+use self::webpki;
+
 type TcpSender = mpsc::UnboundedSender<oneshot::Sender<TcpConnSender>>;
 type TcpConnSender = mpsc::UnboundedSender<(Option<Vec<u8>>, oneshot::Sender<io::Result<Option<Vec<u8>>>>)>;
 
+/// What a TLS client connect sends back once the handshake completes:
+/// the byte-pump channel plus the identity info negotiated.
+type TlsHandshakeResult = (TcpConnSender, Option<Vec<u8>>, Option<Vec<Vec<u8>>>);
+type TlsSender = mpsc::UnboundedSender<oneshot::Sender<TlsHandshakeResult>>;
+
+type ClientTlsStream = tokio_rustls::TlsStream<TcpStream, rustls::ClientSession>;
+type ServerTlsStream = tokio_rustls::TlsStream<TcpStream, rustls::ServerSession>;
+
 pub fn client(addr: SocketAddr) -> TcpClient {
     let tx = run_client(addr);
     TcpClient {
@@ -239,3 +253,279 @@ fn run_server(tcp: TcpServer) -> server::Listening {
         conn_count,
     }
 }
+
+// ===== TLS support =====
+//
+// Mirrors the plaintext `TcpClient`/`TcpServer`/`TcpConn` above, but wraps
+// the accepted/connected `TcpStream` in a rustls session before handing it
+// to the same read/write pump, so mTLS integration tests can drive the
+// proxy's TLS transport end to end. The plaintext API above is untouched.
+
+pub fn client_tls(addr: SocketAddr, config: Arc<rustls::ClientConfig>, name: &str) -> TlsTcpClient {
+    let tx = run_tls_client(addr, config, name.into());
+    TlsTcpClient {
+        addr,
+        tx,
+    }
+}
+
+pub fn server_tls(config: Arc<rustls::ServerConfig>) -> TlsTcpServer {
+    TlsTcpServer {
+        accepts: VecDeque::new(),
+        config,
+    }
+}
+
+pub struct TlsTcpClient {
+    addr: SocketAddr,
+    tx: TlsSender,
+}
+
+pub struct TlsTcpServer {
+    accepts: VecDeque<TlsHandler>,
+    config: Arc<rustls::ServerConfig>,
+}
+
+/// A connected (and handshaken) TLS stream, along with the identity
+/// information negotiated during the handshake. Reads and writes are
+/// pumped the same way as the plaintext `TcpConn`.
+pub struct TlsTcpConn {
+    addr: SocketAddr,
+    tx: TcpConnSender,
+    alpn_protocol: Option<Vec<u8>>,
+    peer_certificates: Option<Vec<Vec<u8>>>,
+}
+
+impl TlsTcpClient {
+    pub fn connect(&self) -> TlsTcpConn {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.unbounded_send(tx);
+        let (tx, alpn_protocol, peer_certificates) = rx
+            .map_err(|_| panic!("tls connect dropped"))
+            .wait()
+            .unwrap();
+        TlsTcpConn {
+            addr: self.addr,
+            tx,
+            alpn_protocol,
+            peer_certificates,
+        }
+    }
+}
+
+impl TlsTcpServer {
+    /// Like `TcpServer::accept`, but `cb` runs against the plaintext bytes
+    /// that come out of the rustls session after the handshake completes.
+    pub fn accept<F, U>(self, cb: F) -> Self
+    where
+        F: FnOnce(Vec<u8>) -> U + Send + 'static,
+        U: Into<Vec<u8>>,
+    {
+        self.accept_fut(move |tls| {
+            tokio_io::io::read(tls, vec![0; 1024])
+                .and_then(move |(tls, mut vec, n)| {
+                    vec.truncate(n);
+                    let write = cb(vec).into();
+                    tokio_io::io::write_all(tls, write)
+                })
+                .map(|_| ())
+                .map_err(|e| panic!("tls server error: {}", e))
+        })
+    }
+
+    pub fn accept_fut<F, U>(mut self, cb: F) -> Self
+    where
+        F: FnOnce(ServerTlsStream) -> U + Send + 'static,
+        U: IntoFuture<Item=(), Error=()> + 'static,
+    {
+        self.accepts.push_back(Box::new(move |tls| -> Box<Future<Item=(), Error=()>> {
+            Box::new(cb(tls).into_future())
+        }));
+        self
+    }
+
+    pub fn run(self) -> server::Listening {
+        run_tls_server(self)
+    }
+}
+
+impl TlsTcpConn {
+    /// The ALPN protocol the handshake negotiated, if any.
+    pub fn negotiated_alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol.as_ref().map(|v| v.as_slice())
+    }
+
+    /// The peer's certificate chain (DER-encoded), if the handshake
+    /// presented one.
+    pub fn peer_certificates(&self) -> Option<&[Vec<u8>]> {
+        self.peer_certificates.as_ref().map(|v| v.as_slice())
+    }
+
+    pub fn read(&self) -> Vec<u8> {
+        self
+            .try_read()
+            .unwrap_or_else(|e| {
+                panic!("TlsTcpConn(addr={}) read() error: {:?}", self.addr, e)
+            })
+    }
+
+    pub fn try_read(&self) -> io::Result<Vec<u8>> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.unbounded_send((None, tx));
+        rx.map_err(|_| panic!("tls read dropped"))
+            .map(|res| res.map(|opt| opt.unwrap()))
+            .wait()
+            .unwrap()
+    }
+
+    pub fn write<T: Into<Vec<u8>>>(&self, buf: T) {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.unbounded_send((Some(buf.into()), tx));
+        rx.map_err(|_| panic!("tls write dropped"))
+            .map(|rsp| assert!(rsp.unwrap().is_none()))
+            .wait()
+            .unwrap()
+    }
+}
+
+type TlsHandler = Box<TlsCallBox + Send>;
+
+trait TlsCallBox: 'static {
+    fn call_box(self: Box<Self>, sock: ServerTlsStream) -> Box<Future<Item=(), Error=()>>;
+}
+
+impl<F: FnOnce(ServerTlsStream) -> Box<Future<Item=(), Error=()>> + Send + 'static> TlsCallBox for F {
+    fn call_box(self: Box<Self>, sock: ServerTlsStream) -> Box<Future<Item=(), Error=()>> {
+        (*self)(sock)
+    }
+}
+
+fn run_tls_client(addr: SocketAddr, config: Arc<rustls::ClientConfig>, name: String)
+    -> TlsSender
+{
+    let (tx, rx) = mpsc::unbounded();
+    let thread_name = format!("support tls client (addr={})", addr);
+    ::std::thread::Builder::new().name(thread_name).spawn(move || {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let work = rx.for_each(move |cb: oneshot::Sender<_>| {
+            let config = config.clone();
+            let name = name.clone();
+            let dns_name = webpki::DNSNameRef::try_from_ascii_str(&name)
+                .expect("invalid TLS server name");
+            let fut = TcpStream::connect(&addr, &handle)
+                .map_err(|e| panic!("connect error: {}", e))
+                .and_then(move |tcp| {
+                    config.connect_async(dns_name, tcp)
+                        .map_err(|e| panic!("tls handshake error: {}", e))
+                })
+                .and_then(move |tls| {
+                    let (session, _) = tls.get_ref();
+                    let alpn_protocol = session.get_alpn_protocol().map(Into::into);
+                    let peer_certificates = session.get_peer_certificates()
+                        .map(|certs| certs.into_iter().map(|c| c.0).collect());
+
+                    let (tx_conn, rx_conn) = mpsc::unbounded();
+                    cb.send((tx_conn, alpn_protocol, peer_certificates)).unwrap();
+
+                    rx_conn.fold(tls, |tls, (action, cb): (Option<Vec<u8>>, oneshot::Sender<io::Result<Option<Vec<u8>>>>)| {
+                        let f: Box<Future<Item=ClientTlsStream, Error=()>> = match action {
+                            None => {
+                                Box::new(tokio_io::io::read(tls, vec![0; 1024])
+                                    .then(move |res| {
+                                        match res {
+                                            Ok((tls, mut vec, n)) => {
+                                                vec.truncate(n);
+                                                cb.send(Ok(Some(vec))).unwrap();
+                                                Ok(tls)
+                                            }
+                                            Err(e) => {
+                                                cb.send(Err(e)).unwrap();
+                                                Err(())
+                                            }
+                                        }
+                                    }))
+                            },
+                            Some(vec) => {
+                                Box::new(tokio_io::io::write_all(tls, vec)
+                                    .then(move |res| {
+                                        match res {
+                                            Ok((tls, _)) => {
+                                                cb.send(Ok(None)).unwrap();
+                                                Ok(tls)
+                                            },
+                                            Err(e) => {
+                                                cb.send(Err(e)).unwrap();
+                                                Err(())
+                                            }
+                                        }
+                                    }))
+                            }
+                        };
+                        f
+                    })
+                        .map(|_| ())
+                        .map_err(|_| ())
+                });
+
+            handle.spawn(fut);
+            Ok(())
+        }).map_err(|e| println!("tls client error: {:?}", e));
+        core.run(work).unwrap();
+    }).unwrap();
+    tx
+}
+
+fn run_tls_server(tcp: TlsTcpServer) -> server::Listening {
+    let (tx, rx) = shutdown_signal();
+    let (started_tx, started_rx) = oneshot::channel();
+    let conn_count = Arc::new(AtomicUsize::from(0));
+    let srv_conn_count = Arc::clone(&conn_count);
+    let any_port = SocketAddr::from(([127, 0, 0, 1], 0));
+    let std_listener = StdTcpListener::bind(&any_port).expect("bind");
+    let addr = std_listener.local_addr().expect("local_addr");
+    let config = tcp.config;
+    let thread_name = format!("support tls server (addr={})", addr);
+    ::std::thread::Builder::new().name(thread_name).spawn(move || {
+        let mut core = Core::new().unwrap();
+        let reactor = core.handle();
+
+        let bind = TcpListener::from_listener(
+            std_listener,
+            &addr,
+            &reactor
+        ).expect("from_listener");
+
+        let mut accepts = tcp.accepts;
+
+        let listen = bind
+            .incoming()
+            .for_each(move |(sock, _)| {
+                let cb = accepts.pop_front().expect("no more accepts");
+                let config = config.clone();
+                let reactor2 = reactor.clone();
+                srv_conn_count.fetch_add(1, Ordering::Release);
+
+                let fut = config.accept_async(sock)
+                    .map_err(|e| panic!("tls accept error: {}", e))
+                    .and_then(move |tls| cb.call_box(tls));
+
+                reactor2.spawn(fut);
+                Ok(())
+            })
+            .map_err(|e| panic!("tls accept error: {}", e));
+
+        core.handle().spawn(listen);
+
+        let _ = started_tx.send(());
+        core.run(rx).unwrap();
+    }).unwrap();
+
+    started_rx.wait().expect("support tls server started");
+    server::Listening {
+        addr,
+        shutdown: tx,
+        conn_count,
+    }
+}